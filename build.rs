@@ -3,7 +3,7 @@
 use {
     bindgen::{EnumVariation, Formatter, MacroTypeVariation},
     std::{
-        env, error, fmt,
+        env, error, fmt, fs,
         path::{Path, PathBuf},
         process::{Command, ExitStatus},
         result, str,
@@ -46,6 +46,13 @@ const BEARSSL_SOURCE_PATH_VAR: &str = "BEARSSL_SOURCE_PATH";
 /// The env var that has the directory we search for BearSSL
 /// header files.
 const BEARSSL_INCLUDE_PATH_VAR: &str = "BEARSSL_INCLUDE_PATH";
+/// The env var that, when set to a truthy value, selects dynamic
+/// linking against a system-installed `libbearssl` instead of
+/// compiling from source.
+const BEARSSL_SHARED_LIB_VAR: &str = "BEARSSL_SHARED_LIB";
+/// The env var that has the directory we search for a
+/// system-installed `libbearssl` when `BEARSSL_SHARED_LIB` is set.
+const BEARSSL_LIB_PATH_VAR: &str = "BEARSSL_LIB_PATH";
 /// The env var that has the git hash we checkout if neither
 /// BEARSSL_PRECOMPILED_PATH nor BEARSSL_SOURCE_PATH are provided.
 const BEARSSL_GIT_HASH_VAR: &str = "BEARSSL_GIT_HASH";
@@ -53,15 +60,102 @@ const BEARSSL_GIT_HASH_VAR: &str = "BEARSSL_GIT_HASH";
 ///
 /// This is master as of 2023/06/05.
 const BEARSSL_GIT_HASH: &str = "79c060eea3eea1257797f15ea1608a9a9923aa6f";
-/// The directory the baked-in BearSSL sources are cloned into.
+/// The directory the baked-in BearSSL sources are cloned into, and
+/// (for the `vendored` feature) checked in directly.
 const BEARSSL_DEPS_PATH: &str = "deps/bearssl";
+/// The name of the file inside the vendored BearSSL tree that
+/// records the snapshot's provenance label, checked against
+/// `VENDORED_COMMIT` so a truncated or substituted vendor directory
+/// is rejected rather than silently compiled as-is.
+///
+/// This is deliberately a separate label from `BEARSSL_GIT_HASH`:
+/// the vendored snapshot isn't guaranteed to be cut from that exact
+/// upstream commit (see `deps/bearssl/README.md`).
+const VENDORED_COMMIT_FILE: &str = "VENDORED_COMMIT";
+/// The expected contents of `VENDORED_COMMIT_FILE`.
+const VENDORED_COMMIT: &str = "bearssl-sys-0.0.4-snapshot";
 
 enum Sources {
     Precompiled(PathBuf),
     Raw(PathBuf),
+    SystemShared(Option<PathBuf>),
+}
+
+/// Standard system library directories searched for a
+/// system-installed `libbearssl` when `BEARSSL_LIB_PATH` isn't set.
+const DEFAULT_SYSTEM_LIB_DIRS: &[&str] = &[
+    "/usr/lib",
+    "/usr/lib64",
+    "/usr/local/lib",
+    "/usr/local/lib64",
+    "/usr/lib/x86_64-linux-gnu",
+    "/usr/lib/aarch64-linux-gnu",
+];
+
+fn env_is_truthy(var: &str) -> bool {
+    matches!(env::var(var).as_deref(), Ok("1" | "true" | "yes"))
+}
+
+/// Locates the `vendored` feature's checked-in BearSSL snapshot and
+/// asserts that it's both the expected snapshot and actually
+/// populated, before trusting it, so a truncated or substituted
+/// vendor directory fails loudly here instead of surfacing as an
+/// empty static archive or a missing-header panic out of bindgen.
+fn vendored_bearssl_dir() -> Result<PathBuf> {
+    let dir = Path::new(&env::var("CARGO_MANIFEST_DIR")?).join(BEARSSL_DEPS_PATH);
+
+    let actual = fs::read_to_string(dir.join(VENDORED_COMMIT_FILE)).map_err(|e| {
+        format!(
+            "unable to read vendored BearSSL commit marker at {:?}: {e}",
+            dir.join(VENDORED_COMMIT_FILE)
+        )
+    })?;
+    let actual = actual.trim();
+    if actual != VENDORED_COMMIT {
+        return Err(format!(
+            "vendored BearSSL snapshot is {actual:?}, but {VENDORED_COMMIT:?} was expected"
+        )
+        .into());
+    }
+
+    if !dir.join("inc").join("bearssl.h").exists() {
+        return Err(format!(
+            "vendored BearSSL snapshot at {:?} is missing inc/bearssl.h; \
+             the `vendored` feature requires a populated deps/bearssl tree, not just the commit marker",
+            dir
+        )
+        .into());
+    }
+    if find(&dir, "src/**/*.c")?.is_empty() {
+        return Err(format!(
+            "vendored BearSSL snapshot at {:?} has no .c sources under src/",
+            dir
+        )
+        .into());
+    }
+
+    Ok(dir)
 }
 
 fn find_bearssl_sources() -> Result<Sources> {
+    println!("cargo:rerun-if-env-changed={BEARSSL_SHARED_LIB_VAR}");
+    println!("cargo:rerun-if-env-changed={BEARSSL_LIB_PATH_VAR}");
+    if env_is_truthy(BEARSSL_SHARED_LIB_VAR) {
+        let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+        if target_os.is_empty() || target_os == "none" {
+            return Err(format!(
+                "{BEARSSL_SHARED_LIB_VAR} is set, but dynamic linking isn't viable on target `{}`",
+                env::var("TARGET").unwrap_or_default()
+            )
+            .into());
+        }
+
+        let lib_path = env::var(BEARSSL_LIB_PATH_VAR)
+            .ok()
+            .map(|v| Path::new(&v).to_owned());
+        return Ok(Sources::SystemShared(lib_path));
+    }
+
     println!("cargo:rerun-if-env-changed={BEARSSL_PRECOMPILED_PATH_VAR}");
     if let Ok(dir) = env::var(BEARSSL_PRECOMPILED_PATH_VAR) {
         let path = Path::new(&dir);
@@ -78,6 +172,10 @@ fn find_bearssl_sources() -> Result<Sources> {
         }
     }
 
+    if cfg!(feature = "vendored") {
+        return Ok(Sources::Raw(vendored_bearssl_dir()?));
+    }
+
     println!("cargo:rerun-if-env-changed={BEARSSL_GIT_HASH_VAR}");
     let path = Path::new(&env::var("OUT_DIR")?).join(BEARSSL_DEPS_PATH);
     if !path.join("Makefile").exists() {
@@ -115,35 +213,253 @@ fn find(root: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
         .map_err(Into::into)
 }
 
+/// Adds BearSSL's architecture- and compiler-specific optimization
+/// macros to `build` so that the portable word implementations are
+/// bypassed on capable CPUs.
+///
+/// Gated behind the `native-optimizations` feature so that callers
+/// who need reproducible, portable builds can opt out.
+fn add_native_optimizations(build: &mut cc::Build) {
+    if cfg!(not(feature = "native-optimizations")) {
+        return;
+    }
+
+    let mut defines = Vec::new();
+
+    let target_arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    let target_pointer_width = env::var("CARGO_CFG_TARGET_POINTER_WIDTH").unwrap_or_default();
+
+    if target_arch == "x86_64" {
+        defines.push("BR_64");
+        if build.get_compiler().is_like_gnu() || build.get_compiler().is_like_clang() {
+            defines.push("BR_INT128");
+        } else if build.get_compiler().is_like_msvc() {
+            defines.push("BR_UMUL128");
+        }
+
+        let target_features = env::var("CARGO_CFG_TARGET_FEATURE").unwrap_or_default();
+        if target_features.split(',').any(|f| f == "sse2") {
+            defines.push("BR_SSE2");
+        }
+        if target_features.split(',').any(|f| f == "aes") {
+            defines.push("BR_AES_X86NI");
+        }
+    } else if target_pointer_width == "64" {
+        defines.push("BR_64");
+    }
+
+    if defines.is_empty() {
+        return;
+    }
+
+    println!(
+        "cargo:warning=applying native BearSSL optimizations: {}",
+        defines.join(", ")
+    );
+    for define in defines {
+        build.define(define, "1");
+    }
+}
+
+/// BearSSL cargo features that select which subsystems get compiled
+/// and exposed, so `no_std` users aren't forced to pay for the whole
+/// TLS stack just to, say, hash a buffer.
+const SUBSYSTEM_FEATURES: &[&str] = &["hash", "symmetric", "ec", "rsa", "x509", "ssl", "prng"];
+
+fn enabled_subsystems() -> Vec<&'static str> {
+    SUBSYSTEM_FEATURES
+        .iter()
+        .copied()
+        .filter(|feature| match *feature {
+            "hash" => cfg!(feature = "hash"),
+            "symmetric" => cfg!(feature = "symmetric"),
+            "ec" => cfg!(feature = "ec"),
+            "rsa" => cfg!(feature = "rsa"),
+            "x509" => cfg!(feature = "x509"),
+            "ssl" => cfg!(feature = "ssl"),
+            "prng" => cfg!(feature = "prng"),
+            _ => false,
+        })
+        .collect()
+}
+
+/// The BearSSL source subdirectories that make up a subsystem.
+fn subsystem_source_dirs(subsystem: &str) -> &'static [&'static str] {
+    match subsystem {
+        "hash" => &["hash"],
+        "symmetric" => &["symcipher", "aead", "mac"],
+        "ec" => &["ec"],
+        "rsa" => &["rsa"],
+        "x509" => &["x509"],
+        "ssl" => &["ssl"],
+        "prng" => &["rand"],
+        _ => &[],
+    }
+}
+
+/// Lists the `.c` files to compile for the enabled subsystems. When
+/// no subsystem feature is enabled, everything is compiled, matching
+/// the crate's historical behavior.
+fn source_files(dir: &Path, subsystems: &[&str]) -> Result<Vec<PathBuf>> {
+    if subsystems.is_empty() {
+        return find(dir, "src/**/*.c");
+    }
+
+    // `codec` (encoding helpers) and `int` (big-integer routines) are
+    // foundational utilities every subsystem above depends on. `hash`
+    // is kept in too, regardless of selection: `src/lib.rs`'s
+    // `br_sha{256,512}_update` wrappers reference `br_sha*` symbols
+    // unconditionally, and BearSSL's RSA/EC/X.509/SSL code paths lean
+    // on hashing internally as well.
+    let mut files = find(dir, "src/codec/*.c")?;
+    files.extend(find(dir, "src/int/*.c")?);
+    files.extend(find(dir, "src/hash/*.c")?);
+    for subsystem in subsystems {
+        if *subsystem == "hash" {
+            continue;
+        }
+        for subdir in subsystem_source_dirs(subsystem) {
+            files.extend(find(dir, &format!("src/{subdir}/*.c"))?);
+        }
+    }
+    Ok(files)
+}
+
+/// The real BearSSL symbol prefixes a subsystem exposes, as declared
+/// in `deps/bearssl/inc/bearssl_*.h`. These don't follow the cargo
+/// feature names: e.g. `hash` functions are `br_sha256_*`/`br_md5_*`/
+/// etc, not `br_hash_*`, and `ec`'s signing functions live under the
+/// separate `br_ecdsa_*` prefix.
+fn subsystem_symbol_prefixes(subsystem: &str) -> &'static [&'static str] {
+    match subsystem {
+        "hash" => &[
+            "md5",
+            "md5sha1",
+            "sha1",
+            "sha224",
+            "sha256",
+            "sha384",
+            "sha512",
+            "multihash",
+            "ghash",
+        ],
+        "symmetric" => &["aes", "des", "chacha20", "poly1305", "gcm", "hmac"],
+        "ec" => &["ec", "ecdsa"],
+        "rsa" => &["rsa"],
+        "x509" => &["x509", "skey"],
+        "ssl" => &["ssl", "sslio", "tls10", "tls12"],
+        "prng" => &["hmac_drbg"],
+        _ => &[],
+    }
+}
+
+/// The bindgen `allowlist_function`/`allowlist_type`/`allowlist_var`
+/// patterns for the enabled subsystems, narrowed to the real BearSSL
+/// symbol prefixes those subsystems expose (see
+/// `subsystem_symbol_prefixes`). `hash` is always included alongside
+/// whatever's selected (see `source_files`). When no subsystem
+/// feature is enabled, every `br_*` symbol is allowlisted.
+fn allowlist_patterns(subsystems: &[&str]) -> (String, String, String) {
+    if subsystems.is_empty() {
+        return (
+            "br_.*".to_owned(),
+            "br_.*".to_owned(),
+            "(br|BR)_.*".to_owned(),
+        );
+    }
+
+    let mut with_hash: Vec<&str> = subsystems.to_vec();
+    if !with_hash.contains(&"hash") {
+        with_hash.push("hash");
+    }
+    let alternation = with_hash
+        .iter()
+        .flat_map(|subsystem| subsystem_symbol_prefixes(subsystem))
+        .copied()
+        .collect::<Vec<_>>()
+        .join("|");
+    (
+        format!("br_({alternation})_.*"),
+        format!("br_({alternation})_.*"),
+        format!("(br|BR)_({alternation})_.*"),
+    )
+}
+
 fn main() -> Result<()> {
-    let src_dir = match find_bearssl_sources()? {
-        Sources::Precompiled(dir) => dir,
+    let subsystems = enabled_subsystems();
+
+    let include_path = match find_bearssl_sources()? {
+        Sources::Precompiled(dir) => {
+            println!("cargo:rerun-if-env-changed={BEARSSL_INCLUDE_PATH_VAR}");
+            env::var(BEARSSL_INCLUDE_PATH_VAR)
+                .map_or_else(|_| dir.join("inc"), |v| Path::new(&v).to_owned())
+        }
         Sources::Raw(dir) => {
             println!("cargo:warning=compiling BearSSL at {:?}", dir);
 
-            cc::Build::new()
+            let mut build = cc::Build::new();
+            build
                 .include(dir.join("inc"))
                 .include(dir.join("src"))
-                .files(find(&dir, "src/**/*.c")?)
-                .opt_level_str("s")
-                .compile("bearssl");
+                .files(source_files(&dir, &subsystems)?)
+                .opt_level_str("s");
+            add_native_optimizations(&mut build);
+            build.compile("bearssl");
 
-            dir
+            println!("cargo:rerun-if-env-changed={BEARSSL_INCLUDE_PATH_VAR}");
+            env::var(BEARSSL_INCLUDE_PATH_VAR)
+                .map_or_else(|_| dir.join("inc"), |v| Path::new(&v).to_owned())
+        }
+        Sources::SystemShared(lib_path) => {
+            println!("cargo:warning=linking against system libbearssl");
+
+            if let Some(lib_path) = &lib_path {
+                println!("cargo:rustc-link-search=native={}", lib_path.display());
+            } else {
+                // No BEARSSL_LIB_PATH given: fall back to the
+                // standard locations a distro- or vendor-provided
+                // libbearssl would install to.
+                for dir in DEFAULT_SYSTEM_LIB_DIRS {
+                    if Path::new(dir).exists() {
+                        println!("cargo:rustc-link-search=native={dir}");
+                    }
+                }
+            }
+            println!("cargo:rustc-link-lib=dylib=bearssl");
+
+            println!("cargo:rerun-if-env-changed={BEARSSL_INCLUDE_PATH_VAR}");
+            match env::var(BEARSSL_INCLUDE_PATH_VAR) {
+                Ok(v) => Path::new(&v).to_owned(),
+                Err(_) => {
+                    return Err(format!(
+                    "{BEARSSL_INCLUDE_PATH_VAR} must be set when {BEARSSL_SHARED_LIB_VAR} is used"
+                )
+                    .into())
+                }
+            }
         }
     };
 
-    println!("cargo:rerun-if-env-changed={BEARSSL_INCLUDE_PATH_VAR}");
-    let include_path = env::var(BEARSSL_INCLUDE_PATH_VAR)
-        .map_or_else(|_| src_dir.join("inc"), |v| Path::new(&v).to_owned());
+    // `size_t` is `usize` on mainstream targets, but on exotic ABIs
+    // (segmented/near-far, CHERI-like) it isn't, so the `libc-ctypes`
+    // feature switches to `::libc` and lets bindgen figure out the
+    // real type instead of assuming `usize`.
+    let (ctypes_prefix, size_t_is_usize) = if cfg!(feature = "libc-ctypes") {
+        ("::libc", false)
+    } else {
+        ("::core::ffi", true)
+    };
+
+    let (fn_allowlist, type_allowlist, var_allowlist) = allowlist_patterns(&subsystems);
 
     let mut builder = bindgen::Builder::default()
         .header(include_path.join("bearssl.h").to_str().unwrap())
-        .allowlist_function("br_.*")
-        .allowlist_type("br_.*")
-        .allowlist_var("(br|BR)_.*")
+        .allowlist_function(fn_allowlist)
+        .allowlist_type(type_allowlist)
+        .allowlist_var(var_allowlist)
         .array_pointers_in_arguments(true)
         .clang_args(&["-I", include_path.to_str().unwrap()])
-        .ctypes_prefix("::core::ffi")
+        .ctypes_prefix(ctypes_prefix)
         .default_enum_style(EnumVariation::NewType {
             is_bitfield: false,
             is_global: false,
@@ -160,7 +476,7 @@ fn main() -> Result<()> {
         .layout_tests(true)
         .merge_extern_blocks(true)
         .prepend_enum_name(true)
-        .size_t_is_usize(true)
+        .size_t_is_usize(size_t_is_usize)
         .time_phases(true)
         .use_core();
 
@@ -12,10 +12,22 @@
 )]
 #![no_std]
 
-use core::{
-    convert::TryInto,
-    ffi::{c_char, c_int, c_uint, c_ulong, c_void},
-};
+use core::convert::TryInto;
+#[cfg(not(feature = "libc-ctypes"))]
+use core::ffi::{c_char, c_int, c_uint, c_ulong, c_void};
+#[cfg(feature = "libc-ctypes")]
+use libc::{c_char, c_int, c_uint, c_ulong, c_void};
+
+// bindgen maps C `size_t` straight to `usize` (`size_t_is_usize(true)`,
+// the default), but `libc-ctypes` turns that off so exotic ABIs where
+// `size_t` isn't `usize` get the real detected type instead — which on
+// every target `libc` supports is `c_ulong`. Mirror that choice here so
+// `len` below matches whatever bindgen generated for the `br_xxx_update`
+// functions it wraps.
+#[cfg(not(feature = "libc-ctypes"))]
+type size_t = usize;
+#[cfg(feature = "libc-ctypes")]
+type size_t = c_ulong;
 
 #[allow(clippy::useless_transmute, clippy::derive_partial_eq_without_eq)]
 mod generated {
@@ -28,7 +40,7 @@ pub use generated::*;
 /// # Safety
 ///
 /// See the `br_xxx_update` docs.
-pub unsafe fn br_sha256_update(ctx: *mut br_sha256_context, data: *const c_void, len: usize) {
+pub unsafe fn br_sha256_update(ctx: *mut br_sha256_context, data: *const c_void, len: size_t) {
     br_sha224_update(ctx, data, len)
 }
 
@@ -37,6 +49,6 @@ pub unsafe fn br_sha256_update(ctx: *mut br_sha256_context, data: *const c_void,
 /// # Safety
 ///
 /// See the `br_xxx_update` docs.
-pub unsafe fn br_sha512_update(ctx: *mut br_sha512_context, data: *const c_void, len: usize) {
+pub unsafe fn br_sha512_update(ctx: *mut br_sha512_context, data: *const c_void, len: size_t) {
     br_sha384_update(ctx, data, len)
 }